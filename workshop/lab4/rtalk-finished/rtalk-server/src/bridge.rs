@@ -0,0 +1,287 @@
+//! Relays a room's traffic to an external chat network. A bridge registers itself as
+//! a synthetic `User` in `State` (so `Session::broadcast_to_room` delivers to it like
+//! any other connection) and translates between local `Event`s and whatever protocol
+//! the remote network speaks.
+
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use futures::select;
+use futures_util::future::FutureExt;
+use irc::client::prelude::{Client, Command, Config as IrcConfig};
+use irc::proto::Message as IrcMessage;
+use tokio::stream::StreamExt;
+use tokio::sync::mpsc::Receiver;
+
+use rtalk_codec::Event;
+
+use crate::{RoomName, Session};
+
+/// Maps local room names to remote channel names and back, so a bridge can be told
+/// "relay #rtalk/general to #general" without hardcoding the mapping in the bridge
+/// itself.
+pub struct LinkMap {
+    room_to_channel: BTreeMap<RoomName, String>,
+    channel_to_room: BTreeMap<String, RoomName>,
+}
+
+impl LinkMap {
+    pub fn new(links: Vec<(RoomName, String)>) -> Self {
+        let mut room_to_channel = BTreeMap::new();
+        let mut channel_to_room = BTreeMap::new();
+        for (room, channel) in links {
+            room_to_channel.insert(room.clone(), channel.clone());
+            channel_to_room.insert(channel, room);
+        }
+        LinkMap {
+            room_to_channel,
+            channel_to_room,
+        }
+    }
+
+    fn channel_for(&self, room: &str) -> Option<&str> {
+        self.room_to_channel.get(room).map(String::as_str)
+    }
+
+    fn room_for(&self, channel: &str) -> Option<&str> {
+        self.channel_to_room.get(channel).map(String::as_str)
+    }
+}
+
+/// A subsystem that relays one `Session` room to/from an external network. The local
+/// side drives `handle_local` with every event the bridge's synthetic user receives;
+/// the bridge drives its own loop pushing remote traffic back in as `Event`s.
+#[async_trait]
+pub trait Bridge: Send + Sync {
+    async fn handle_local(&self, event: Event);
+}
+
+pub struct IrcBridgeConfig {
+    pub server: String,
+    pub port: u16,
+    pub nickname: String,
+    pub use_tls: bool,
+    pub links: LinkMap,
+}
+
+impl IrcBridgeConfig {
+    /// Builds a config from `RTALK_IRC_*` env vars, or returns `None` if
+    /// `RTALK_IRC_SERVER` isn't set -- the bridge is opt-in, so no server means no
+    /// bridge is started. `RTALK_IRC_LINKS` is a comma-separated list of
+    /// `room=channel` pairs, e.g. `general=#rtalk-general,random=#rtalk-random`.
+    pub fn from_env() -> Option<Self> {
+        let server = std::env::var("RTALK_IRC_SERVER").ok()?;
+        let port = match std::env::var("RTALK_IRC_PORT") {
+            Ok(port) => match port.parse() {
+                Ok(port) => port,
+                Err(_) => {
+                    log::warn!("RTALK_IRC_PORT {:?} is not a valid port, using 6667", port);
+                    6667
+                }
+            },
+            Err(_) => 6667,
+        };
+        let nickname =
+            std::env::var("RTALK_IRC_NICKNAME").unwrap_or_else(|_| "rtalk-bridge".to_string());
+        let use_tls = std::env::var("RTALK_IRC_TLS")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        let links = std::env::var("RTALK_IRC_LINKS")
+            .map(|raw| parse_links(&raw))
+            .unwrap_or_default();
+
+        Some(IrcBridgeConfig {
+            server,
+            port,
+            nickname,
+            use_tls,
+            links: LinkMap::new(links),
+        })
+    }
+}
+
+/// Parses `room=channel,room=channel,...`, dropping any pair that's missing a room or
+/// a channel rather than failing the whole config over one bad entry -- but logs each
+/// one dropped, so a typo'd link shows up at startup instead of as a room that silently
+/// never reaches IRC.
+fn parse_links(raw: &str) -> Vec<(RoomName, String)> {
+    raw.split(',')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let room = parts.next().unwrap_or("").trim();
+            let channel = parts.next().unwrap_or("").trim();
+            if room.is_empty() || channel.is_empty() {
+                log::warn!("ignoring malformed RTALK_IRC_LINKS entry {:?}", pair);
+                return None;
+            }
+            Some((room.to_string(), channel.to_string()))
+        })
+        .collect()
+}
+
+pub struct IrcBridge {
+    nickname: String,
+    links: LinkMap,
+    irc_sink: irc::client::ClientStream,
+    client: Client,
+}
+
+impl IrcBridge {
+    async fn connect(config: IrcBridgeConfig) -> Result<Self, irc::error::Error> {
+        let irc_config = IrcConfig {
+            nickname: Some(config.nickname.clone()),
+            server: Some(config.server),
+            port: Some(config.port),
+            use_tls: Some(config.use_tls),
+            channels: config.links.room_to_channel.values().cloned().collect(),
+            ..IrcConfig::default()
+        };
+
+        let mut client = Client::from_config(irc_config).await?;
+        client.identify()?;
+        let irc_sink = client.stream()?;
+
+        Ok(IrcBridge {
+            nickname: config.nickname,
+            links: config.links,
+            irc_sink,
+            client,
+        })
+    }
+}
+
+#[async_trait]
+impl Bridge for IrcBridge {
+    async fn handle_local(&self, event: Event) {
+        match event {
+            Event::MessageReceived(room, who, text) => {
+                if let Some(channel) = self.links.channel_for(&room) {
+                    let _ = self.client.send_privmsg(channel, format!("{}: {}", who, text));
+                }
+            }
+            // Deliberately PRIVMSG, not a literal IRC JOIN/PART: this bridge holds a
+            // single IRC identity (`self.nickname`), and IRC has no way for one
+            // identity to announce a join/part on another's behalf. Emitting a real
+            // JOIN/PART here would move the *bridge's own* membership in the channel
+            // every time any local user joins or left the room -- including parting
+            // the bridge off the channel entirely the moment an unrelated local user
+            // disconnects. Announcing as chat text is what single-identity bridges do.
+            Event::Joined(room, who) => {
+                if let Some(channel) = self.links.channel_for(&room) {
+                    let _ = self
+                        .client
+                        .send_privmsg(channel, format!("* {} joined", who));
+                }
+            }
+            Event::Left(room, who) => {
+                if let Some(channel) = self.links.channel_for(&room) {
+                    let _ = self
+                        .client
+                        .send_privmsg(channel, format!("* {} left", who));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Registers the bridge as a synthetic user in `session`, then runs its event loop
+/// until the connection to the remote network drops. The synthetic user's id is what
+/// lets `broadcast_to_room` fan local events out to `handle_local` like any other
+/// member; IRC traffic going the other way is turned into `Event::MessageReceived` and
+/// re-broadcast to whichever room the link map says the source channel belongs to.
+pub async fn spawn_irc_bridge(
+    session: Session,
+    config: IrcBridgeConfig,
+) -> Result<(), irc::error::Error> {
+    let rooms: Vec<RoomName> = config.links.room_to_channel.keys().cloned().collect();
+    let nickname = config.nickname.clone();
+    let mut bridge = IrcBridge::connect(config).await?;
+
+    let (id, mut rx): (u64, Receiver<Event>) =
+        session.add_synthetic_user(format!("irc-bridge ({})", nickname));
+    for room in rooms {
+        session.join_room(id, room);
+    }
+
+    tokio::spawn(async move {
+        loop {
+            select! {
+                local_event = rx.next().fuse() => {
+                    match local_event {
+                        Some(event) => bridge.handle_local(event).await,
+                        None => break,
+                    }
+                }
+                remote = bridge.irc_sink.next().fuse() => {
+                    match remote {
+                        Some(Ok(message)) => handle_remote_message(&session, id, &bridge, &message).await,
+                        Some(Err(_)) | None => break,
+                    }
+                }
+            }
+        }
+
+        session.disconnect(vec![id]).await;
+    });
+
+    Ok(())
+}
+
+/// Translates an inbound IRC `PRIVMSG`/`JOIN`/`PART` into the matching local `Event`
+/// and broadcasts it to the linked room, skipping messages the bridge itself just
+/// echoed back (our own nickname as the source).
+///
+/// `Event` carries no origin id of its own, so the broadcast is addressed by `bridge_id`
+/// instead (`broadcast_to_room_except`): without it, the bridge's own synthetic user —
+/// a member of every linked room — would receive the event this same function just
+/// caused and `handle_local` would relay it straight back out to IRC, echoing every line.
+async fn handle_remote_message(
+    session: &Session,
+    bridge_id: u64,
+    bridge: &IrcBridge,
+    message: &IrcMessage,
+) {
+    let source_nick = match message.source_nickname() {
+        Some(nick) if nick != bridge.nickname => nick.to_string(),
+        _ => return,
+    };
+
+    match &message.command {
+        Command::PRIVMSG(channel, text) => {
+            if let Some(room) = bridge.links.room_for(channel) {
+                let room = room.to_string();
+                let who = source_nick.clone();
+                let text = text.clone();
+                session
+                    .broadcast_to_room_except(&room, bridge_id, || {
+                        Event::MessageReceived(room.clone(), who.clone(), text.clone())
+                    })
+                    .await;
+            }
+        }
+        Command::JOIN(channel, _, _) => {
+            if let Some(room) = bridge.links.room_for(channel) {
+                let room = room.to_string();
+                let who = source_nick.clone();
+                session
+                    .broadcast_to_room_except(&room, bridge_id, || {
+                        Event::Joined(room.clone(), who.clone())
+                    })
+                    .await;
+            }
+        }
+        Command::PART(channel, _) => {
+            if let Some(room) = bridge.links.room_for(channel) {
+                let room = room.to_string();
+                let who = source_nick.clone();
+                session
+                    .broadcast_to_room_except(&room, bridge_id, || {
+                        Event::Left(room.clone(), who.clone())
+                    })
+                    .await;
+            }
+        }
+        _ => {}
+    }
+}
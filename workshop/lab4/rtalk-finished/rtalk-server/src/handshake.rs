@@ -0,0 +1,591 @@
+//! Noise-XX-style encrypted handshake run over the raw `TcpStream` before it is ever
+//! handed to `EventCodec`. Each peer has a static X25519 identity key; after the
+//! handshake both sides hold a pair of ChaCha20-Poly1305 keys (one per direction) and
+//! every frame written through `EncryptedTransport` is sealed under an incrementing
+//! nonce, so `ClientConnection` downstream never sees plaintext bytes off the wire.
+
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+const PROTOCOL_NAME: &[u8] = b"Noise_XX_25519_ChaChaPoly_SHA256";
+
+/// This peer's long-lived identity. The server loads one at startup; clients present
+/// theirs during the handshake so the server can check it against an allow-list.
+pub struct StaticKeypair {
+    secret: StaticSecret,
+    pub public: PublicKey,
+}
+
+impl StaticKeypair {
+    pub fn generate() -> Self {
+        let secret = StaticSecret::new(OsRng);
+        let public = PublicKey::from(&secret);
+        StaticKeypair { secret, public }
+    }
+
+    pub fn from_secret_bytes(bytes: [u8; 32]) -> Self {
+        let secret = StaticSecret::from(bytes);
+        let public = PublicKey::from(&secret);
+        StaticKeypair { secret, public }
+    }
+}
+
+/// Server-side handshake policy: our own identity, plus an optional allow-list of
+/// client static keys. `None` means any client static key is accepted (still
+/// authenticated, just not authorized against a roster).
+pub struct HandshakeConfig {
+    pub server_key: StaticKeypair,
+    pub allowed_client_keys: Option<Vec<PublicKey>>,
+}
+
+impl HandshakeConfig {
+    /// Loads the server static key from `RTALK_SERVER_KEY` (32-byte hex) if set,
+    /// otherwise generates a fresh one (fine for local testing; it won't survive a
+    /// restart, so set the env var for anything long-lived). `RTALK_ALLOWED_CLIENT_KEYS`,
+    /// if set, is a comma-separated list of 32-byte hex client static keys — clients
+    /// presenting any other static key are rejected during the handshake.
+    ///
+    /// Returns `Err` instead of panicking if either env var is set to something that
+    /// isn't valid 32-byte hex, so a typo'd key shows up as a clear startup error rather
+    /// than a panic or a silently truncated/zero-padded key.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let server_key = match std::env::var("RTALK_SERVER_KEY") {
+            Ok(hex) => StaticKeypair::from_secret_bytes(decode_hex32(&hex)?),
+            Err(_) => StaticKeypair::generate(),
+        };
+
+        let allowed_client_keys = match std::env::var("RTALK_ALLOWED_CLIENT_KEYS") {
+            Ok(list) => {
+                let mut keys = Vec::new();
+                for hex in list.split(',').filter(|key| !key.is_empty()) {
+                    keys.push(PublicKey::from(decode_hex32(hex)?));
+                }
+                Some(keys)
+            }
+            Err(_) => None,
+        };
+
+        Ok(HandshakeConfig {
+            server_key,
+            allowed_client_keys,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError(pub String);
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid handshake config: {}", self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Parses exactly 64 hex characters into 32 bytes. Rejects anything shorter, longer, or
+/// non-hex instead of zero-padding or truncating, so a malformed key is a config error
+/// and not a silently weakened one.
+fn decode_hex32(hex: &str) -> Result<[u8; 32], ConfigError> {
+    if hex.len() != 64 {
+        return Err(ConfigError(format!(
+            "expected 64 hex characters (32 bytes), got {}",
+            hex.len()
+        )));
+    }
+
+    let mut bytes = [0u8; 32];
+    for (i, chunk) in hex.as_bytes().chunks(2).enumerate() {
+        let s = std::str::from_utf8(chunk)
+            .map_err(|_| ConfigError(format!("key is not valid hex: {:?}", hex)))?;
+        bytes[i] = u8::from_str_radix(s, 16)
+            .map_err(|_| ConfigError(format!("key is not valid hex: {:?}", hex)))?;
+    }
+    Ok(bytes)
+}
+
+#[derive(Debug)]
+pub struct HandshakeError(pub String);
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "handshake failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<HandshakeError> for io::Error {
+    fn from(e: HandshakeError) -> Self {
+        io::Error::new(io::ErrorKind::PermissionDenied, e.0)
+    }
+}
+
+/// Running Noise symmetric state: chaining key + handshake hash, mixed into as the
+/// handshake progresses, and used at the end to derive the two directional keys.
+struct SymmetricState {
+    ck: [u8; 32],
+    h: [u8; 32],
+}
+
+impl SymmetricState {
+    fn initialize() -> Self {
+        let h = Sha256::digest(PROTOCOL_NAME).into();
+        SymmetricState { ck: h, h }
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.update(self.h);
+        hasher.update(data);
+        self.h = hasher.finalize().into();
+    }
+
+    fn mix_key(&mut self, dh_out: &[u8]) -> [u8; 32] {
+        let hk = Hkdf::<Sha256>::new(Some(&self.ck), dh_out);
+        let mut okm = [0u8; 64];
+        hk.expand(&[], &mut okm).expect("hkdf expand");
+        self.ck.copy_from_slice(&okm[..32]);
+        let mut k = [0u8; 32];
+        k.copy_from_slice(&okm[32..]);
+        k
+    }
+
+    fn encrypt_and_hash(&mut self, k: &[u8; 32], plaintext: &[u8]) -> Vec<u8> {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(k));
+        let nonce = Nonce::from_slice(&[0u8; 12]);
+        let ciphertext = cipher
+            .encrypt(nonce, plaintext)
+            .expect("handshake payload encryption");
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    fn decrypt_and_hash(&mut self, k: &[u8; 32], ciphertext: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(k));
+        let nonce = Nonce::from_slice(&[0u8; 12]);
+        let plaintext = cipher
+            .decrypt(nonce, ciphertext)
+            .map_err(|_| HandshakeError("bad handshake MAC".to_string()))?;
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    /// Splits the final chaining key into the two transport keys, `(c1, c2)`, per the
+    /// Noise spec's `Split()`: the initiator encrypts with `c1` and decrypts with `c2`;
+    /// the responder does the opposite. Callers pick `(send, recv)` accordingly — see
+    /// `respond`, which swaps them for the responder side.
+    fn split(self) -> (SessionKeys, SessionKeys) {
+        let hk = Hkdf::<Sha256>::new(Some(&self.ck), &[]);
+        let mut okm = [0u8; 64];
+        hk.expand(&[], &mut okm).expect("hkdf expand");
+        let mut c1 = [0u8; 32];
+        let mut c2 = [0u8; 32];
+        c1.copy_from_slice(&okm[..32]);
+        c2.copy_from_slice(&okm[32..]);
+        (
+            SessionKeys { key: c1 },
+            SessionKeys { key: c2 },
+        )
+    }
+}
+
+/// One direction's transport key, handed to `EncryptedTransport`.
+#[derive(Clone)]
+pub struct SessionKeys {
+    key: [u8; 32],
+}
+
+fn hmac_auth(key: &[u8; 32], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("hmac key");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// Runs the responder side of the XX pattern over an already-connected `stream`,
+/// rejecting the peer if its static key isn't on `config.allowed_client_keys`.
+/// Returns the (send, recv) keys for `EncryptedTransport`, from the server's point of
+/// view — `send` encrypts frames going to the client, `recv` decrypts frames coming in.
+pub async fn respond<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    config: &HandshakeConfig,
+) -> Result<(SessionKeys, SessionKeys), HandshakeError> {
+    let mut st = SymmetricState::initialize();
+
+    // -> e
+    let client_e = read_key(stream).await?;
+    st.mix_hash(client_e.as_bytes());
+
+    // <- e, ee, s, es
+    let server_e = EphemeralSecret::new(OsRng);
+    let server_e_pub = PublicKey::from(&server_e);
+    write_key(stream, &server_e_pub).await?;
+    st.mix_hash(server_e_pub.as_bytes());
+
+    let ee = server_e.diffie_hellman(&client_e);
+    let k = st.mix_key(ee.as_bytes());
+
+    let s_ciphertext = st.encrypt_and_hash(&k, config.server_key.public.as_bytes());
+    write_frame(stream, &s_ciphertext).await?;
+
+    let es = config.server_key.secret.diffie_hellman(&client_e);
+    let k = st.mix_key(es.as_bytes());
+
+    // -> s, se
+    let s_ciphertext = read_frame(stream).await?;
+    let client_static_bytes = st.decrypt_and_hash(&k, &s_ciphertext)?;
+    if client_static_bytes.len() != 32 {
+        return Err(HandshakeError("malformed client static key".to_string()));
+    }
+    let mut client_static_raw = [0u8; 32];
+    client_static_raw.copy_from_slice(&client_static_bytes);
+    let client_static = PublicKey::from(client_static_raw);
+
+    if let Some(allowed) = &config.allowed_client_keys {
+        if !allowed
+            .iter()
+            .any(|key| key.as_bytes() == client_static.as_bytes())
+        {
+            return Err(HandshakeError(
+                "client static key is not on the allow-list".to_string(),
+            ));
+        }
+    }
+
+    let se = server_e.diffie_hellman(&client_static);
+    let _ = se; // folded into ck via mix_key below
+    let k = st.mix_key(se.as_bytes());
+
+    let mac_frame = read_frame(stream).await?;
+    let expected_mac = hmac_auth(&k, &st.h);
+    if mac_frame != expected_mac {
+        return Err(HandshakeError("client transcript MAC mismatch".to_string()));
+    }
+
+    // We're the responder: per Split()'s convention, c1 is the initiator's send key
+    // (our recv) and c2 is the responder's send key (our send) -- see `split`.
+    let (c1, c2) = st.split();
+    Ok((c2, c1))
+}
+
+async fn read_key<S: AsyncRead + Unpin>(stream: &mut S) -> Result<PublicKey, HandshakeError> {
+    let mut buf = [0u8; 32];
+    stream
+        .read_exact(&mut buf)
+        .await
+        .map_err(|e| HandshakeError(format!("reading public key: {}", e)))?;
+    Ok(PublicKey::from(buf))
+}
+
+async fn write_key<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    key: &PublicKey,
+) -> Result<(), HandshakeError> {
+    stream
+        .write_all(key.as_bytes())
+        .await
+        .map_err(|e| HandshakeError(format!("writing public key: {}", e)))
+}
+
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>, HandshakeError> {
+    let mut len_buf = [0u8; 2];
+    stream
+        .read_exact(&mut len_buf)
+        .await
+        .map_err(|e| HandshakeError(format!("reading frame length: {}", e)))?;
+    let len = u16::from_be_bytes(len_buf) as usize;
+    let mut buf = vec![0u8; len];
+    stream
+        .read_exact(&mut buf)
+        .await
+        .map_err(|e| HandshakeError(format!("reading frame: {}", e)))?;
+    Ok(buf)
+}
+
+async fn write_frame<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    frame: &[u8],
+) -> Result<(), HandshakeError> {
+    let len = u16::try_from(frame.len())
+        .map_err(|_| HandshakeError("handshake frame too large".to_string()))?;
+    stream
+        .write_all(&len.to_be_bytes())
+        .await
+        .map_err(|e| HandshakeError(format!("writing frame length: {}", e)))?;
+    stream
+        .write_all(frame)
+        .await
+        .map_err(|e| HandshakeError(format!("writing frame: {}", e)))
+}
+
+/// Wraps a transport in ChaCha20-Poly1305, sealing/opening one length-prefixed frame
+/// per write/read cycle with a nonce that increments per direction so a replayed or
+/// reordered frame fails to decrypt. `EventCodec` is framed on top of this adapter, so
+/// it only ever sees plaintext `Event` bytes.
+pub struct EncryptedTransport<S> {
+    inner: S,
+    send_key: [u8; 32],
+    recv_key: [u8; 32],
+    send_nonce: u64,
+    recv_nonce: u64,
+    // Encrypted bytes queued for `inner` but not yet accepted by its `poll_write`.
+    write_pending: Vec<u8>,
+    // Raw bytes read from `inner` that don't yet form a complete frame.
+    read_raw: Vec<u8>,
+    // Decrypted bytes from a completed frame, waiting to be copied into a caller's buf.
+    read_plain: VecDeque<u8>,
+}
+
+impl<S> EncryptedTransport<S> {
+    pub fn new(inner: S, send: SessionKeys, recv: SessionKeys) -> Self {
+        EncryptedTransport {
+            inner,
+            send_key: send.key,
+            recv_key: recv.key,
+            send_nonce: 0,
+            recv_nonce: 0,
+            write_pending: Vec::new(),
+            read_raw: Vec::new(),
+            read_plain: VecDeque::new(),
+        }
+    }
+
+    fn nonce_bytes(counter: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&counter.to_le_bytes());
+        nonce
+    }
+}
+
+impl<S: AsyncWrite + Unpin> EncryptedTransport<S> {
+    /// Pushes as much of `write_pending` into `inner` as it will currently accept.
+    fn drain_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_pending.is_empty() {
+            match Pin::new(&mut self.inner).poll_write(cx, &self.write_pending) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write encrypted frame",
+                    )))
+                }
+                Poll::Ready(Ok(n)) => {
+                    self.write_pending.drain(..n);
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for EncryptedTransport<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        loop {
+            if !this.read_plain.is_empty() {
+                let n = std::cmp::min(buf.len(), this.read_plain.len());
+                for slot in buf.iter_mut().take(n) {
+                    *slot = this.read_plain.pop_front().unwrap();
+                }
+                return Poll::Ready(Ok(n));
+            }
+
+            let want = if this.read_raw.len() < 4 {
+                4
+            } else {
+                let len = u32::from_be_bytes([
+                    this.read_raw[0],
+                    this.read_raw[1],
+                    this.read_raw[2],
+                    this.read_raw[3],
+                ]) as usize;
+                4 + len
+            };
+
+            if this.read_raw.len() >= want && want > 4 {
+                let frame: Vec<u8> = this.read_raw.drain(..want).collect();
+                let ciphertext = &frame[4..];
+
+                let nonce = Self::nonce_bytes(this.recv_nonce);
+                this.recv_nonce += 1;
+                let cipher = ChaCha20Poly1305::new(Key::from_slice(&this.recv_key));
+                let plaintext = cipher
+                    .decrypt(Nonce::from_slice(&nonce), ciphertext)
+                    .map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidData, "failed to decrypt frame")
+                    })?;
+                this.read_plain.extend(plaintext);
+                continue;
+            }
+
+            let mut tmp = [0u8; 4096];
+            match Pin::new(&mut this.inner).poll_read(cx, &mut tmp) {
+                Poll::Ready(Ok(0)) => {
+                    return if this.read_raw.is_empty() {
+                        Poll::Ready(Ok(0))
+                    } else {
+                        Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "connection closed mid-frame",
+                        )))
+                    }
+                }
+                Poll::Ready(Ok(n)) => this.read_raw.extend_from_slice(&tmp[..n]),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for EncryptedTransport<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if !this.write_pending.is_empty() {
+            match this.drain_pending(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Ready(Ok(())) => {}
+            }
+        }
+
+        let nonce = Self::nonce_bytes(this.send_nonce);
+        this.send_nonce += 1;
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&this.send_key));
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), buf)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "failed to encrypt frame"))?;
+
+        let len = ciphertext.len() as u32;
+        this.write_pending.extend_from_slice(&len.to_be_bytes());
+        this.write_pending.extend_from_slice(&ciphertext);
+
+        match this.drain_pending(cx) {
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending | Poll::Ready(Ok(())) => Poll::Ready(Ok(buf.len())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.drain_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// The initiator side of the XX pattern, mirroring `respond` step for step. There's
+    /// no client binary in this repo to exercise `respond` against, so this exists only
+    /// to pin down the handshake's key directionality in a test.
+    async fn initiate(
+        stream: &mut TcpStream,
+        client_key: &StaticKeypair,
+    ) -> Result<(SessionKeys, SessionKeys), HandshakeError> {
+        let mut st = SymmetricState::initialize();
+
+        // -> e
+        let client_e = EphemeralSecret::new(OsRng);
+        let client_e_pub = PublicKey::from(&client_e);
+        write_key(stream, &client_e_pub).await?;
+        st.mix_hash(client_e_pub.as_bytes());
+
+        // <- e, ee, s, es
+        let server_e = read_key(stream).await?;
+        st.mix_hash(server_e.as_bytes());
+
+        let ee = client_e.diffie_hellman(&server_e);
+        let k = st.mix_key(ee.as_bytes());
+
+        let s_ciphertext = read_frame(stream).await?;
+        let server_static_bytes = st.decrypt_and_hash(&k, &s_ciphertext)?;
+        if server_static_bytes.len() != 32 {
+            return Err(HandshakeError("malformed server static key".to_string()));
+        }
+        let mut server_static_raw = [0u8; 32];
+        server_static_raw.copy_from_slice(&server_static_bytes);
+        let server_static = PublicKey::from(server_static_raw);
+
+        let es = client_e.diffie_hellman(&server_static);
+        let k = st.mix_key(es.as_bytes());
+
+        // -> s, se
+        let s_ciphertext = st.encrypt_and_hash(&k, client_key.public.as_bytes());
+        write_frame(stream, &s_ciphertext).await?;
+
+        let se = client_key.secret.diffie_hellman(&server_e);
+        let k = st.mix_key(se.as_bytes());
+
+        let mac = hmac_auth(&k, &st.h);
+        write_frame(stream, &mac).await?;
+
+        // We're the initiator: c1 is our send key, c2 is our recv key.
+        let (c1, c2) = st.split();
+        Ok((c1, c2))
+    }
+
+    #[tokio::test]
+    async fn respond_and_initiate_agree_on_directional_keys() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let config = HandshakeConfig {
+            server_key: StaticKeypair::generate(),
+            allowed_client_keys: None,
+        };
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            respond(&mut socket, &config).await.unwrap()
+        });
+
+        let mut client_socket = TcpStream::connect(addr).await.unwrap();
+        let client_key = StaticKeypair::generate();
+        let (client_send, client_recv) = initiate(&mut client_socket, &client_key).await.unwrap();
+        let (server_send, server_recv) = server.await.unwrap();
+
+        // What one side sends, the other must recv with -- not the same key on both
+        // sides, which is the bug this test guards against.
+        assert_eq!(client_send.key, server_recv.key);
+        assert_eq!(server_send.key, client_recv.key);
+        assert_ne!(client_send.key, client_recv.key);
+    }
+
+    #[test]
+    fn decode_hex32_rejects_malformed_input() {
+        assert!(decode_hex32(&"ab".repeat(32)).is_ok());
+        assert!(decode_hex32("too short").is_err());
+        assert!(decode_hex32(&"zz".repeat(32)).is_err());
+    }
+}
@@ -1,23 +1,40 @@
 #![recursion_limit = "512"]
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::pin::Pin;
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use futures::{future, select};
 use futures_util::{future::FutureExt, sink::SinkExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::stream::StreamExt;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
 use tokio_util::codec::{Decoder, Framed};
 
+/// How long `main` waits, after telling every client the server is shutting down, for
+/// their connection tasks to finish flushing before it exits anyway.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
 use rtalk_codec::{Event, EventCodec};
 
+mod bridge;
+mod handshake;
+mod metrics;
+
+use handshake::{EncryptedTransport, HandshakeConfig};
+use metrics::Metrics;
+
+pub(crate) type RoomName = String;
+
 pub struct User {
     name: Option<String>,
     ip: std::net::SocketAddr,
     sender: Sender<Event>,
+    rooms: BTreeSet<RoomName>,
 }
 
 impl User {
@@ -29,12 +46,19 @@ impl User {
     }
 }
 
+#[derive(Default)]
+struct Room {
+    members: BTreeSet<u64>,
+}
+
 struct State {
     counter: u64,
     users: BTreeMap<u64, User>,
+    rooms: BTreeMap<RoomName, Room>,
+    tasks: BTreeMap<u64, JoinHandle<()>>,
 }
 
-type ClientConnection = Pin<Box<Framed<TcpStream, EventCodec>>>;
+type ClientConnection = Pin<Box<Framed<EncryptedTransport<TcpStream>, EventCodec>>>;
 
 impl State {
     fn add_user(
@@ -49,41 +73,82 @@ impl State {
 
         let (sender, mut rx) = mpsc::channel::<Event>(100);
 
-        let _task = tokio::spawn(async move {
+        let task = tokio::spawn(async move {
+            // Set when the connection dies unexpectedly (send failure, EOF, or a read
+            // error) so we prune the user from `State` exactly once, after the loop.
+            let mut dropped = false;
+
             loop {
                 select! {
 
                     // from session to network
                     event = rx.next().fuse() => {
                         if let Some(event) = event {
-                            network.send(event).await.expect("Message send failed.");
+                            // `network.send` flushes the sink, so by the time this
+                            // returns the client has the shutdown notice; there's
+                            // nothing further for this task to do, so it exits instead
+                            // of idling until the client drops its socket.
+                            let is_shutdown = matches!(event, Event::ServerShutdown(_));
+                            if network.send(event).await.is_err() {
+                                dropped = true;
+                                break;
+                            }
+                            if is_shutdown {
+                                break;
+                            }
                         }
                     },
 
                     // from network
                     event = network.next().fuse() => {
-                        if let Some(Ok(event)) = event {
-                            match event {
+                        match event {
+                            Some(Ok(event)) => match event {
                                 Event::RequestJoin(name) => {
-                                    let name: String = session.update_user(id, name.clone());
-                                    session.broadcast(|| Event::Joined(name.clone())).await;
+                                    session.update_user(id, name.clone());
+                                }
+                                Event::JoinRoom(room) => {
+                                    let name = session.join_room(id, room.clone());
+                                    session.broadcast_to_room(&room, || Event::Joined(room.clone(), name.clone())).await;
+                                }
+                                Event::LeaveRoom(room) => {
+                                    let name = session.leave_room(id, room.clone());
+                                    session.broadcast_to_room(&room, || Event::Left(room.clone(), name.clone())).await;
                                 }
                                 Event::Leave() => {
-                                    let name = session.remove_user(id);
-                                    session.broadcast(|| Event::Left(name.clone())).await;
+                                    session.disconnect(vec![id]).await;
                                     break;
                                 }
-                                Event::MessageSend(msg) => {
+                                Event::MessageSend(room, msg) => {
                                     let who = session.get_name(id);
-                                    session.broadcast(|| Event::MessageReceived(who.clone(), msg.clone())).await;
+                                    session.broadcast_to_room(&room, || Event::MessageReceived(room.clone(), who.clone(), msg.clone())).await;
+                                }
+                                // Server->client-only variants (Joined, MessageReceived,
+                                // ServerShutdown, ...) have no business arriving from a
+                                // client; a misbehaving or malicious peer shouldn't be able
+                                // to take down its own connection task over it.
+                                other => {
+                                    log::warn!(
+                                        "{}: ignoring unexpected {} event from client",
+                                        session.get_name(id),
+                                        metrics::event_kind(&other)
+                                    );
                                 }
-                                _ => unimplemented!()
+                            },
+                            // Client dropped the connection or the socket errored: there's
+                            // nothing left to read, so clean up like any other disconnect.
+                            Some(Err(_)) | None => {
+                                dropped = true;
+                                break;
                             }
                         }
                     }
                     complete => break,
                 }
             }
+
+            if dropped {
+                session.disconnect(vec![id]).await;
+            }
         });
 
         self.users.insert(
@@ -92,8 +157,10 @@ impl State {
                 name: None,
                 ip,
                 sender,
+                rooms: BTreeSet::new(),
             },
         );
+        self.tasks.insert(self.counter, task);
 
         self.counter
     }
@@ -108,28 +175,113 @@ impl State {
         user.name = Some(name);
         user.get_name()
     }
+
+    fn join_room(&mut self, id: u64, room: RoomName) -> String {
+        let name = {
+            let user = self.users.get_mut(&id).unwrap();
+            user.rooms.insert(room.clone());
+            user.get_name()
+        };
+        self.rooms.entry(room).or_default().members.insert(id);
+        name
+    }
+
+    fn leave_room(&mut self, id: u64, room: RoomName) -> String {
+        let name = {
+            let user = self.users.get_mut(&id).unwrap();
+            user.rooms.remove(&room);
+            user.get_name()
+        };
+        if let Some(members) = self.rooms.get_mut(&room) {
+            members.members.remove(&id);
+        }
+        name
+    }
+
+    /// Registers a synthetic user with no backing `ClientConnection`; its `Sender` is
+    /// how `broadcast`/`broadcast_to_room` reach it, and the returned `Receiver` is
+    /// what the caller (a bridge) polls instead of a network socket.
+    fn add_synthetic_user(&mut self, name: String) -> (u64, mpsc::Receiver<Event>) {
+        self.counter += 1;
+        let id = self.counter;
+
+        let (sender, rx) = mpsc::channel::<Event>(100);
+        self.users.insert(
+            id,
+            User {
+                name: Some(name),
+                ip: "0.0.0.0:0".parse().unwrap(),
+                sender,
+                rooms: BTreeSet::new(),
+            },
+        );
+
+        (id, rx)
+    }
+
+    fn room_members(&self, room: &str) -> Vec<u64> {
+        self.rooms
+            .get(room)
+            .map(|room| room.members.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Removes a user if still present, pruning them from every room they were in and
+    /// dropping their `JoinHandle` from `tasks` (it's only needed so `shutdown` can wait
+    /// on still-connected tasks; once a user is gone there's nothing left to join).
+    /// Returns `None` if the user was already removed, so callers can disconnect the
+    /// same id twice (e.g. a failed send racing an explicit `Leave`) without panicking.
+    fn remove_user(&mut self, id: u64) -> Option<(String, BTreeSet<RoomName>)> {
+        let user = self.users.remove(&id)?;
+        for room in &user.rooms {
+            if let Some(room) = self.rooms.get_mut(room) {
+                room.members.remove(&id);
+            }
+        }
+        self.tasks.remove(&id);
+        Some((user.get_name(), user.rooms))
+    }
+
+    fn take_tasks(&mut self) -> Vec<JoinHandle<()>> {
+        std::mem::take(&mut self.tasks).into_iter().map(|(_, task)| task).collect()
+    }
 }
 
 #[derive(Clone)]
 pub struct Session {
     state: Arc<RwLock<State>>,
+    metrics: Arc<Metrics>,
 }
 
 impl Session {
-    fn new() -> Self {
+    fn new(metrics: Arc<Metrics>) -> Self {
         Session {
             state: Arc::new(RwLock::new(State {
                 counter: 0,
                 users: BTreeMap::new(),
+                rooms: BTreeMap::new(),
+                tasks: BTreeMap::new(),
             })),
+            metrics,
         }
     }
 
+    /// Updates the `connected_users` gauge from the live user count. Called after
+    /// anything that adds or removes a `State::users` entry.
+    fn refresh_connected_users_gauge(&self) {
+        let count = self.state.read().unwrap().users.len() as i64;
+        self.metrics.connected_users.set(count);
+    }
+
     fn add_user(&self, ip: std::net::SocketAddr, connection: ClientConnection) -> u64 {
-        self.state
+        let id = self
+            .state
             .write()
             .unwrap()
-            .add_user(self.clone(), ip, connection)
+            .add_user(self.clone(), ip, connection);
+        self.metrics.connections_accepted.inc();
+        self.refresh_connected_users_gauge();
+        id
     }
 
     fn get_name(&self, id: u64) -> String {
@@ -140,9 +292,52 @@ impl Session {
         self.state.write().unwrap().update_user(id, name)
     }
 
-    fn remove_user(&self, id: u64) -> String {
-        let user = self.state.write().unwrap().users.remove(&id).unwrap();
-        user.get_name()
+    pub(crate) fn join_room(&self, id: u64, room: RoomName) -> String {
+        self.state.write().unwrap().join_room(id, room)
+    }
+
+    fn leave_room(&self, id: u64, room: RoomName) -> String {
+        self.state.write().unwrap().leave_room(id, room)
+    }
+
+    /// Registers a synthetic user with no underlying TCP connection — used by bridges
+    /// so they receive room broadcasts through the same `Sender<Event>` path as any
+    /// other member, without pretending to be a real `ClientConnection`.
+    pub(crate) fn add_synthetic_user(&self, name: String) -> (u64, mpsc::Receiver<Event>) {
+        let result = self.state.write().unwrap().add_synthetic_user(name);
+        self.refresh_connected_users_gauge();
+        result
+    }
+
+    /// Removes the given users (already-removed ids are ignored) and broadcasts `Left`
+    /// to every room each of them was in.
+    pub(crate) async fn disconnect(&self, ids: Vec<u64>) {
+        let removed: Vec<(String, BTreeSet<RoomName>)> = {
+            let mut state = self.state.write().unwrap();
+            ids.into_iter().filter_map(|id| state.remove_user(id)).collect()
+        };
+        self.refresh_connected_users_gauge();
+
+        for (name, rooms) in removed {
+            for room in rooms {
+                self.broadcast_to_room(&room, || Event::Left(room.clone(), name.clone()))
+                    .await;
+            }
+        }
+    }
+
+    /// Tells every connected client the server is going away, then waits for their
+    /// connection tasks to flush the notice and exit. `SHUTDOWN_GRACE_PERIOD` is a
+    /// backstop for peers that never exit on their own (a slow or wedged socket), not
+    /// the expected path: each task breaks its loop as soon as it has sent
+    /// `Event::ServerShutdown`, so this normally returns well before the timeout.
+    async fn shutdown(&self, reason: String) {
+        for id in self.user_ids() {
+            self.send_event(id, Event::ServerShutdown(reason.clone())).await;
+        }
+
+        let tasks = self.state.write().unwrap().take_tasks();
+        let _ = tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, future::join_all(tasks)).await;
     }
 
     fn user_ids(&self) -> Vec<u64> {
@@ -155,28 +350,95 @@ impl Session {
             .collect()
     }
 
-    async fn broadcast<F: Fn() -> Event>(&self, event_gen: F) {
+    pub(crate) async fn broadcast_to_room<F: Fn() -> Event>(&self, room: &str, event_gen: F) {
+        self.broadcast_to_room_excluding(room, None, event_gen).await
+    }
+
+    /// Like `broadcast_to_room`, but skips `exclude`. Used by the IRC bridge when
+    /// relaying a message it just received from the remote side: without this, the
+    /// bridge's own synthetic user (a member of every linked room) would receive the
+    /// broadcast it caused and immediately relay it right back out, echoing every line.
+    pub(crate) async fn broadcast_to_room_except<F: Fn() -> Event>(
+        &self,
+        room: &str,
+        exclude: u64,
+        event_gen: F,
+    ) {
+        self.broadcast_to_room_excluding(room, Some(exclude), event_gen).await
+    }
+
+    async fn broadcast_to_room_excluding<F: Fn() -> Event>(
+        &self,
+        room: &str,
+        exclude: Option<u64>,
+        event_gen: F,
+    ) {
+        let started_at = std::time::Instant::now();
+        self.metrics.record_event(&event_gen());
+        self.metrics.messages_broadcast.inc();
+
         let futs = self
-            .user_ids()
+            .state
+            .read()
+            .unwrap()
+            .room_members(room)
             .into_iter()
-            .map(|dest_id| self.send_event(dest_id, event_gen()));
-        future::join_all(futs).await;
+            .filter(|dest_id| Some(*dest_id) != exclude)
+            .map(|dest_id| {
+                let evt = event_gen();
+                async move { (dest_id, self.send_event(dest_id, evt).await) }
+            });
+
+        let failed: Vec<u64> = future::join_all(futs)
+            .await
+            .into_iter()
+            .filter_map(|(id, ok)| if ok { None } else { Some(id) })
+            .collect();
+
+        self.metrics
+            .broadcast_latency
+            .observe(started_at.elapsed().as_secs_f64());
+
+        if !failed.is_empty() {
+            self.disconnect(failed).await;
+        }
     }
 
-    async fn send_event(&self, id: u64, evt: Event) {
+    /// Queues `evt` for delivery to `id`. Returns `false` if the user's receiver has
+    /// gone away (their connection task already exited) so the caller can prune them
+    /// instead of panicking; a missing user is treated as a no-op success.
+    async fn send_event(&self, id: u64, evt: Event) -> bool {
         let mut sender = {
             let state = self.state.read().unwrap();
-            if let Some(user) = state.users.get(&id) {
-                user.sender.clone()
-            } else {
-                return;
+            match state.users.get(&id) {
+                Some(user) => user.sender.clone(),
+                None => return true,
             }
         };
 
-        sender
-            .send(evt)
-            .await
-            .expect("Could not queue event to send");
+        sender.send(evt).await.is_ok()
+    }
+}
+
+/// Runs the handshake on a freshly accepted socket and, if the peer authenticates,
+/// hands it off to `Session::add_user`. Rejected peers are just dropped: they never
+/// reach `State::users`.
+async fn accept_connection(
+    session: Session,
+    ip: std::net::SocketAddr,
+    mut socket: TcpStream,
+    config: Arc<HandshakeConfig>,
+) {
+    match handshake::respond(&mut socket, &config).await {
+        Ok((send, recv)) => {
+            let transport = EncryptedTransport::new(socket, send, recv);
+            let codec = EventCodec;
+            let connection = Box::pin(codec.framed(transport));
+            session.add_user(ip, connection);
+        }
+        Err(e) => {
+            log::warn!("rejecting connection from {}: {}", ip, e);
+        }
     }
 }
 
@@ -184,15 +446,52 @@ impl Session {
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
-    let session = Session::new();
+    let metrics = Arc::new(Metrics::new());
+    let session = Session::new(metrics.clone());
+    let handshake_config = Arc::new(HandshakeConfig::from_env()?);
 
     let mut listener = TcpListener::bind("127.0.0.1:3215").await?;
-    loop {
-        let (socket, ip) = listener.accept().await?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(metrics, "127.0.0.1:9898".parse().unwrap()).await {
+            log::error!("metrics listener failed: {}", e);
+        }
+    });
 
+    if let Some(bridge_config) = bridge::IrcBridgeConfig::from_env() {
         let session = session.clone();
-        let codec = EventCodec;
-        let connection = Box::pin(codec.framed(socket));
-        session.add_user(ip, connection);
+        tokio::spawn(async move {
+            if let Err(e) = bridge::spawn_irc_bridge(session, bridge_config).await {
+                log::error!("irc bridge failed to start: {}", e);
+            }
+        });
     }
+
+    loop {
+        select! {
+            accepted = listener.accept().fuse() => {
+                let (socket, ip) = accepted?;
+
+                tokio::spawn(accept_connection(
+                    session.clone(),
+                    ip,
+                    socket,
+                    handshake_config.clone(),
+                ));
+            }
+            _ = tokio::signal::ctrl_c().fuse() => {
+                log::info!("received SIGINT, shutting down");
+                session.shutdown("server received SIGINT".to_string()).await;
+                break;
+            }
+            _ = sigterm.recv().fuse() => {
+                log::info!("received SIGTERM, shutting down");
+                session.shutdown("server received SIGTERM".to_string()).await;
+                break;
+            }
+        }
+    }
+
+    Ok(())
 }
@@ -0,0 +1,134 @@
+//! Prometheus observability: a registry of counters/gauges/histograms that `Session`
+//! updates as users connect, disconnect, and broadcasts go out, plus a tiny HTTP
+//! listener that serves them in the text exposition format at `/metrics`.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use rtalk_codec::Event;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+pub struct Metrics {
+    registry: Registry,
+    pub connections_accepted: IntCounter,
+    pub connected_users: IntGauge,
+    pub messages_broadcast: IntCounter,
+    pub events_by_type: IntCounterVec,
+    pub broadcast_latency: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let connections_accepted = IntCounter::new(
+            "rtalk_connections_accepted_total",
+            "Total TCP connections accepted",
+        )
+        .unwrap();
+        let connected_users = IntGauge::new(
+            "rtalk_connected_users",
+            "Users currently tracked in State::users",
+        )
+        .unwrap();
+        let messages_broadcast = IntCounter::new(
+            "rtalk_messages_broadcast_total",
+            "Total broadcast_to_room calls",
+        )
+        .unwrap();
+        let events_by_type = IntCounterVec::new(
+            Opts::new("rtalk_events_total", "Broadcast events, by kind"),
+            &["event"],
+        )
+        .unwrap();
+        let broadcast_latency = Histogram::with_opts(HistogramOpts::new(
+            "rtalk_broadcast_latency_seconds",
+            "Time to fan a broadcast out to every member of a room",
+        ))
+        .unwrap();
+
+        registry
+            .register(Box::new(connections_accepted.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(connected_users.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(messages_broadcast.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(events_by_type.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(broadcast_latency.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            connections_accepted,
+            connected_users,
+            messages_broadcast,
+            events_by_type,
+            broadcast_latency,
+        }
+    }
+
+    pub fn record_event(&self, event: &Event) {
+        self.events_by_type.with_label_values(&[event_kind(event)]).inc();
+    }
+
+    /// Renders every registered metric in the Prometheus text exposition format.
+    fn gather(&self) -> Vec<u8> {
+        let metric_families = self.registry.gather();
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&metric_families, &mut buffer)
+            .expect("encode metrics");
+        buffer
+    }
+}
+
+pub(crate) fn event_kind(event: &Event) -> &'static str {
+    match event {
+        Event::RequestJoin(_) => "request_join",
+        Event::JoinRoom(_) => "join_room",
+        Event::LeaveRoom(_) => "leave_room",
+        Event::Leave() => "leave",
+        Event::MessageSend(_, _) => "message_send",
+        Event::Joined(_, _) => "joined",
+        Event::Left(_, _) => "left",
+        Event::MessageReceived(_, _, _) => "message_received",
+        Event::ServerShutdown(_) => "server_shutdown",
+        _ => "other",
+    }
+}
+
+/// Accepts connections on `addr` forever, answering every request with the current
+/// scrape regardless of path or method -- `/metrics` is the only thing this listener
+/// serves, so there's nothing to route.
+pub async fn serve(metrics: Arc<Metrics>, addr: SocketAddr) -> std::io::Result<()> {
+    let mut listener = TcpListener::bind(addr).await?;
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+
+        tokio::spawn(async move {
+            let mut request = [0u8; 1024];
+            let _ = socket.read(&mut request).await;
+
+            let body = metrics.gather();
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+
+            let _ = socket.write_all(header.as_bytes()).await;
+            let _ = socket.write_all(&body).await;
+        });
+    }
+}